@@ -1,8 +1,15 @@
+use crate::fetch::Fetch;
+use crate::sandbox::Sandbox;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AutoFile {
+    /// Variables that `program` and `arguments` can reference as `{{name}}`,
+    /// shared by all tasks unless overridden by a task's own `vars`.
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+
     pub tasks: HashMap<String, Task>,
 }
 
@@ -18,4 +25,37 @@ pub struct Task {
     /// Which tasks need to run before this task can be run in turn
     #[serde(default)]
     pub needs: Vec<String>,
+
+    /// Variables local to this task, overriding top-level `vars` of the same name
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+
+    /// Glob patterns of files this task reads. Used together with `needs` to
+    /// fingerprint the task, so it can be skipped once nothing it depends on
+    /// has changed since the last run.
+    #[serde(default)]
+    pub inputs: Vec<String>,
+
+    /// Glob patterns of files this task produces.
+    #[serde(default)]
+    pub outputs: Vec<String>,
+
+    /// Relative cost/duration hint. When several tasks are runnable at once,
+    /// the one on the longest remaining chain of costs is scheduled first.
+    #[serde(default = "default_cost")]
+    pub cost: u64,
+
+    /// Run this task in fresh Linux namespaces, isolated from the host's
+    /// filesystem and network except for what's explicitly declared
+    #[serde(default)]
+    pub sandbox: Option<Sandbox>,
+
+    /// Files to download and verify before this task runs, made available
+    /// in the task's download directory
+    #[serde(default)]
+    pub fetch: Vec<Fetch>,
+}
+
+fn default_cost() -> u64 {
+    1
 }
@@ -0,0 +1,178 @@
+//! Content-addressed cache of task fingerprints, used to skip tasks whose
+//! resolved command and input files haven't changed since the last run.
+
+use crate::fetch::Fetch;
+use crate::queue::TaskId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::{ResultExt, Snafu};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+const FINGERPRINTS_FILE: &str = "fingerprints.toml";
+
+/// Fingerprint recorded for a task after it last ran successfully.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// Combined hash of the resolved program, arguments, input file
+    /// contents, declared fetches, and the fingerprints of all `needs`.
+    pub hash: String,
+
+    /// Hash of each declared output, as left behind by the last successful run.
+    #[serde(default)]
+    pub outputs: HashMap<String, String>,
+}
+
+/// Persistent cache directory holding the fingerprints from previous runs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Cache {
+    #[serde(default)]
+    fingerprints: HashMap<String, Fingerprint>,
+
+    #[serde(skip)]
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// Load the cache from `dir`, creating the directory if it doesn't exist yet.
+    pub fn open(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir).context(Io { path: dir.to_owned() })?;
+
+        let path = dir.join(FINGERPRINTS_FILE);
+        let mut cache = if path.exists() {
+            let source = std::fs::read_to_string(&path).context(Io { path: path.clone() })?;
+            toml::from_str(&source).context(Parse { path })?
+        } else {
+            Cache::default()
+        };
+        cache.dir = dir.to_owned();
+        Ok(cache)
+    }
+
+    /// Look up the fingerprint recorded for `task` on a previous run, if any.
+    pub fn get(&self, task: &TaskId) -> Option<&Fingerprint> {
+        self.fingerprints.get(task.as_str())
+    }
+
+    /// Record a task's fingerprint and persist the cache to disk.
+    pub fn record(&mut self, task: &TaskId, fingerprint: Fingerprint) -> Result<()> {
+        self.fingerprints
+            .insert(task.as_str().to_owned(), fingerprint);
+
+        let path = self.dir.join(FINGERPRINTS_FILE);
+        let serialized = toml::to_string_pretty(self).context(Encode)?;
+
+        // Write to a temp file and rename it into place atomically, so a
+        // crash or a concurrent `auto` invocation mid-write can't leave
+        // `fingerprints.toml` truncated/corrupted and unparseable on the
+        // next run.
+        let tmp = NamedTempFile::new_in(&self.dir).context(Io {
+            path: self.dir.clone(),
+        })?;
+        std::fs::write(tmp.path(), serialized).context(Io {
+            path: tmp.path().to_owned(),
+        })?;
+        tmp.persist(&path).context(Persist { path })?;
+        Ok(())
+    }
+}
+
+/// Hash every file matched by `patterns`, keyed by the matched path.
+pub fn hash_globs(patterns: &[String]) -> Result<HashMap<String, String>> {
+    let mut hashes = HashMap::new();
+    for pattern in patterns {
+        let matches = glob::glob(pattern).context(GlobPattern {
+            pattern: pattern.clone(),
+        })?;
+        for entry in matches {
+            let path = entry.context(GlobRead {
+                pattern: pattern.clone(),
+            })?;
+            let contents = std::fs::read(&path).context(Io { path: path.clone() })?;
+            hashes.insert(
+                path.to_string_lossy().into_owned(),
+                format!("{:x}", Sha256::digest(&contents)),
+            );
+        }
+    }
+    Ok(hashes)
+}
+
+/// Compute the fingerprint of a task from its resolved program and
+/// arguments, the hashes of its input files, its declared fetches, and the
+/// fingerprints of everything it needs. Re-running with the exact same
+/// fingerprint means nothing the task could observe has changed.
+pub fn fingerprint(
+    program: &OsStr,
+    arguments: &[OsString],
+    inputs: &HashMap<String, String>,
+    fetches: &[Fetch],
+    needs: &[Fingerprint],
+) -> String {
+    let mut hasher = Sha256::new();
+
+    hasher.update(program.to_string_lossy().as_bytes());
+    for arg in arguments {
+        hasher.update(b"\0");
+        hasher.update(arg.to_string_lossy().as_bytes());
+    }
+
+    let mut input_paths: Vec<&String> = inputs.keys().collect();
+    input_paths.sort();
+    for path in input_paths {
+        hasher.update(path.as_bytes());
+        hasher.update(inputs[path].as_bytes());
+    }
+
+    for fetch in fetches {
+        hasher.update(fetch.url.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(fetch.sha256.as_bytes());
+    }
+
+    for need in needs {
+        hasher.update(need.hash.as_bytes());
+    }
+
+    format!("{:x}", hasher.finalize())
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not access cache at {}: {}", path.display(), source))]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Could not parse cache at {}: {}", path.display(), source))]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[snafu(display("Could not serialize cache: {}", source))]
+    Encode { source: toml::ser::Error },
+
+    #[snafu(display("Could not move updated cache into place at {}: {}", path.display(), source))]
+    Persist {
+        path: PathBuf,
+        source: tempfile::PersistError,
+    },
+
+    #[snafu(display("Invalid glob pattern {:?}: {}", pattern, source))]
+    GlobPattern {
+        pattern: String,
+        source: glob::PatternError,
+    },
+
+    #[snafu(display("Could not read file matched by {:?}: {}", pattern, source))]
+    GlobRead {
+        pattern: String,
+        source: glob::GlobError,
+    },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
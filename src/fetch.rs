@@ -0,0 +1,143 @@
+//! Declarative file fetches with SHA-256 verification, backed by a shared,
+//! content-addressed download cache so an artifact already in the cache is
+//! not downloaded again.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::{ResultExt, Snafu};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fetch {
+    /// URL to download
+    pub url: String,
+
+    /// Filename the download is made available as, relative to the task's
+    /// download directory
+    pub dest: String,
+
+    /// Expected SHA-256 digest of the downloaded file, as a hex string
+    pub sha256: String,
+}
+
+/// Make every fetch in `fetches` available under `download_dir`, verified
+/// against its expected digest, reusing `cache_dir` across tasks and runs.
+pub fn ensure_all(fetches: &[Fetch], cache_dir: &Path, download_dir: &Path) -> Result<()> {
+    if fetches.is_empty() {
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(cache_dir).context(Io {
+        path: cache_dir.to_owned(),
+    })?;
+    std::fs::create_dir_all(download_dir).context(Io {
+        path: download_dir.to_owned(),
+    })?;
+
+    for fetch in fetches {
+        ensure_one(fetch, cache_dir, download_dir)?;
+    }
+    Ok(())
+}
+
+fn ensure_one(fetch: &Fetch, cache_dir: &Path, download_dir: &Path) -> Result<()> {
+    let cached_path = cache_dir.join(&fetch.sha256);
+
+    if !cached_path.exists() {
+        // Download into a uniquely-named temp file in the same directory
+        // and only rename it into its final, content-addressed name once
+        // it's verified. Tasks run on their own OS thread (see the `-j`
+        // jobserver support), so two of them can race to fetch the same
+        // URL at the same time; writing straight to `cached_path` would let
+        // one thread observe the other's partially-written file.
+        let tmp = NamedTempFile::new_in(cache_dir).context(Io {
+            path: cache_dir.to_owned(),
+        })?;
+        download(&fetch.url, tmp.path())?;
+
+        let actual = hash_file(tmp.path())?;
+        if actual != fetch.sha256 {
+            return ChecksumMismatch {
+                url: fetch.url.clone(),
+                expected: fetch.sha256.clone(),
+                actual,
+            }
+            .fail();
+        }
+
+        // Renaming within the same directory is atomic, so if another
+        // thread already won the race and populated `cached_path`, this
+        // just replaces it with byte-identical content.
+        tmp.persist(&cached_path).context(Persist {
+            path: cached_path.clone(),
+        })?;
+    }
+
+    let dest_path = download_dir.join(&fetch.dest);
+    if dest_path.exists() {
+        std::fs::remove_file(&dest_path).context(Io {
+            path: dest_path.clone(),
+        })?;
+    }
+    // Prefer a hard link to avoid copying potentially large files; fall back
+    // to a copy if the cache and download dir aren't on the same filesystem.
+    if std::fs::hard_link(&cached_path, &dest_path).is_err() {
+        std::fs::copy(&cached_path, &dest_path).context(Io { path: dest_path })?;
+    }
+
+    Ok(())
+}
+
+fn download(url: &str, dest: &Path) -> Result<()> {
+    let response = reqwest::blocking::get(url)
+        .and_then(|response| response.error_for_status())
+        .context(Download { url })?;
+    let bytes = response.bytes().context(Download { url })?;
+    std::fs::write(dest, &bytes).context(Io {
+        path: dest.to_owned(),
+    })
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path).context(Io {
+        path: path.to_owned(),
+    })?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).context(Io {
+        path: path.to_owned(),
+    })?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not access {}: {}", path.display(), source))]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Could not download {}: {}", url, source))]
+    Download { url: String, source: reqwest::Error },
+
+    #[snafu(display("Could not move downloaded file into cache at {}: {}", path.display(), source))]
+    Persist {
+        path: PathBuf,
+        source: tempfile::PersistError,
+    },
+
+    #[snafu(display(
+        "Checksum mismatch for {}: expected {}, got {}",
+        url,
+        expected,
+        actual
+    ))]
+    ChecksumMismatch {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
@@ -1,11 +1,18 @@
 use ansi_term::Color;
+use handlebars::Handlebars;
+use jobserver::Client as JobserverClient;
 use snafu::{ResultExt, Snafu};
 use std::ffi::OsString;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
 
 mod autofile;
+mod cache;
+mod fetch;
 mod queue;
+mod sandbox;
 use std::{collections::HashSet, path::PathBuf};
 use structopt::StructOpt;
 
@@ -18,6 +25,18 @@ struct Opt {
     /// Input file
     #[structopt(parse(from_os_str), default_value = "Autofile.toml")]
     autofile: PathBuf,
+
+    /// Maximum number of tasks to run at the same time. Defaults to the
+    /// number of available CPUs. Cooperates with GNU make's jobserver
+    /// protocol, so nested `make`/`cargo` invocations share the same budget
+    /// instead of oversubscribing the machine.
+    #[structopt(short = "j", long = "jobs")]
+    jobs: Option<usize>,
+
+    /// Directory used to persist task fingerprints between runs, enabling
+    /// incremental builds
+    #[structopt(long, parse(from_os_str), default_value = ".auto-cache")]
+    cache_dir: PathBuf,
 }
 
 fn main() {
@@ -35,48 +54,113 @@ fn run(opt: Opt) -> Result<()> {
     let autofile: autofile::AutoFile =
         toml::from_str(&source).context(ParseConfig { path: opt.autofile })?;
 
-    let mut plan = queue::TaskQueue::new(autofile.tasks.iter().map(|(id, task)| {
-        queue::Task {
-            id: queue::TaskId(id.clone()),
-            needs: task
-                .needs
-                .iter()
-                .map(|id| queue::TaskId(id.to_owned()))
-                .collect(),
-            payload: Cmd {
-                program: (&task.program).into(),
-                arguments: task.arguments.iter().map(|s| s.into()).collect(),
-            },
-        }
-    }))
-    .context(Planner)?;
+    let mut templates = Handlebars::new();
+    templates.set_strict_mode(true);
+
+    let tasks = autofile
+        .tasks
+        .iter()
+        .map(|(id, task)| {
+            let id = queue::TaskId(id.clone());
+
+            let mut vars = autofile.vars.clone();
+            vars.extend(task.vars.clone());
+
+            let render = |template: &str| {
+                templates
+                    .render_template(template, &vars)
+                    .context(Template { id: id.clone() })
+            };
+
+            Ok(queue::Task {
+                needs: task
+                    .needs
+                    .iter()
+                    .map(|id| queue::TaskId(id.to_owned()))
+                    .collect(),
+                payload: Cmd {
+                    program: render(&task.program)?.into(),
+                    arguments: task
+                        .arguments
+                        .iter()
+                        .map(|s| render(s).map(Into::into))
+                        .collect::<Result<Vec<_>>>()?,
+                    inputs: task.inputs.clone(),
+                    outputs: task.outputs.clone(),
+                    sandbox: task.sandbox.clone(),
+                    fetch: task.fetch.clone(),
+                },
+                cost: task.cost,
+                id,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut plan = queue::TaskQueue::new(tasks).context(Planner)?;
 
     eprintln!("Generated plan for {} tasks", autofile.tasks.len());
 
+    let jobs = opt.jobs.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(std::num::NonZeroUsize::get)
+            .unwrap_or(1)
+    });
+    eprintln!("Running up to {} tasks at a time", jobs);
+
+    // `jobs` total concurrent tasks means `jobs - 1` tokens need to be handed
+    // out to others, since the process itself implicitly holds one slot.
+    let jobserver = JobserverClient::new(jobs.saturating_sub(1)).context(Jobserver)?;
+    // Whether the one slot we hold implicitly (i.e. without reading a token
+    // from the jobserver pipe) is currently free to use.
+    let implicit_slot_free = Arc::new(AtomicBool::new(true));
+
+    let cache = Arc::new(Mutex::new(
+        cache::Cache::open(&opt.cache_dir).context(Cache)?,
+    ));
+
     let outdir = tempfile::tempdir().context(Temp)?;
     eprintln!("Logging output to {}", outdir.path().display());
 
     let mut successful = Vec::new();
+    let mut cached = Vec::new();
     let mut failed = Vec::new();
+    let mut skipped = Vec::new();
 
     let (sender, receiver) = mpsc::channel::<TaskMessage>();
 
     let mut running = HashSet::new();
 
     loop {
-        while let Some(task) = plan.pop_available() {
+        while running.len() < jobs {
+            let task = match plan.pop_available() {
+                Some(task) => task,
+                None => break,
+            };
+
             eprintln!("{} {}", Color::Blue.bold().paint("Running"), task.id);
             running.insert(task.id.clone());
 
             std::thread::spawn({
                 let sender = sender.clone();
                 let outdir_path = outdir.path().to_path_buf();
+                let jobserver = jobserver.clone();
+                let implicit_slot_free = implicit_slot_free.clone();
+                let cache = cache.clone();
+                let cache_dir = opt.cache_dir.clone();
                 move || {
                     let start_time = std::time::Instant::now();
 
-                    let outcome = match run_task(&outdir_path, &task) {
-                        Ok(true) => TaskOutcome::Success,
-                        Ok(false) => TaskOutcome::Failed,
+                    let outcome = match run_task(
+                        &outdir_path,
+                        &cache_dir,
+                        &task,
+                        &jobserver,
+                        &implicit_slot_free,
+                        &cache,
+                    ) {
+                        Ok(RunResult::Success) => TaskOutcome::Success,
+                        Ok(RunResult::Cached) => TaskOutcome::Cached,
+                        Ok(RunResult::Failed) => TaskOutcome::Failed,
                         Err(err) => TaskOutcome::Error(err),
                     };
 
@@ -107,6 +191,16 @@ fn run(opt: Opt) -> Result<()> {
                             plan.mark_done(&result.task.id);
                             successful.push(result.task);
                         }
+                        TaskOutcome::Cached => {
+                            eprintln!(
+                                "{} {} (duration {:.2}s)",
+                                Color::Cyan.bold().paint("Cached"),
+                                result.task.id,
+                                result.duration.as_secs_f64()
+                            );
+                            plan.mark_done(&result.task.id);
+                            cached.push(result.task);
+                        }
                         TaskOutcome::Failed => {
                             eprintln!(
                                 "{} {} (duration {:.2}s)",
@@ -114,6 +208,7 @@ fn run(opt: Opt) -> Result<()> {
                                 result.task.id,
                                 result.duration.as_secs_f64()
                             );
+                            report_skipped(&mut plan, &result.task.id, &mut skipped);
                             failed.push(result.task);
                         }
                         TaskOutcome::Error(err) => {
@@ -124,6 +219,7 @@ fn run(opt: Opt) -> Result<()> {
                                 err,
                                 result.duration.as_secs_f64()
                             );
+                            report_skipped(&mut plan, &result.task.id, &mut skipped);
                             failed.push(result.task);
                         }
                     }
@@ -146,36 +242,186 @@ fn run(opt: Opt) -> Result<()> {
     }
 
     eprintln!(
-        "{} successful, {} failed, {} not started",
+        "{} successful, {} cached, {} failed, {} skipped, {} not started",
         successful.len(),
+        cached.len(),
         failed.len(),
+        skipped.len(),
         not_started.len()
     );
 
     Ok(())
 }
 
-fn run_task(outdir: &Path, task: &queue::Task<Cmd>) -> Result<bool> {
+/// Mark `failed` as failed in `plan` and report every task that is abandoned
+/// as a consequence, so dependents of a failure are explained rather than
+/// just showing up in the flat "not running" list at the end.
+fn report_skipped(
+    plan: &mut queue::TaskQueue<Cmd>,
+    failed: &queue::TaskId,
+    skipped: &mut Vec<queue::Task<Cmd>>,
+) {
+    for skipped_task in plan.mark_failed(failed) {
+        eprintln!(
+            "{} {} (blocked on failed dependency {})",
+            Color::Yellow.bold().paint("  Skipped"),
+            skipped_task.id,
+            failed
+        );
+        skipped.push(skipped_task);
+    }
+}
+
+/// Outcome of dispatching a task, distinguishing a task that was actually
+/// executed from one that was skipped because its fingerprint was unchanged.
+enum RunResult {
+    Cached,
+    Success,
+    Failed,
+}
+
+fn run_task(
+    outdir: &Path,
+    cache_dir: &Path,
+    task: &queue::Task<Cmd>,
+    jobserver: &JobserverClient,
+    implicit_slot_free: &AtomicBool,
+    cache: &Mutex<cache::Cache>,
+) -> Result<RunResult> {
+    let input_hashes = cache::hash_globs(&task.payload.inputs).context(Cache)?;
+
+    // Needs are a set, not a sequence, so order them deterministically before
+    // folding them into the fingerprint.
+    let mut needs: Vec<&queue::TaskId> = task.needs.iter().collect();
+    needs.sort();
+    let needs_fingerprints: Vec<cache::Fingerprint> = {
+        let cache = cache.lock().unwrap();
+        needs
+            .into_iter()
+            .map(|id| {
+                cache
+                    .get(id)
+                    .cloned()
+                    .expect("dependency must have completed and recorded a fingerprint")
+            })
+            .collect()
+    };
+
+    let fingerprint = cache::fingerprint(
+        &task.payload.program,
+        &task.payload.arguments,
+        &input_hashes,
+        &task.payload.fetch,
+        &needs_fingerprints,
+    );
+
+    // Re-hash the declared outputs too, so a task whose recipe/inputs are
+    // unchanged but whose output was deleted or hand-edited since the last
+    // run is not wrongly reported as cached.
+    let current_output_hashes = cache::hash_globs(&task.payload.outputs).context(Cache)?;
+    let up_to_date = {
+        let cache = cache.lock().unwrap();
+        cache.get(&task.id).map_or(false, |recorded| {
+            recorded.hash == fingerprint && recorded.outputs == current_output_hashes
+        })
+    };
+    if up_to_date {
+        return Ok(RunResult::Cached);
+    }
+
+    fetch::ensure_all(
+        &task.payload.fetch,
+        &cache_dir.join("fetch-cache"),
+        &cache_dir.join("downloads").join(task.id.as_str()),
+    )
+    .context(Fetch)?;
+
     // Create files for redirecting output
     let task_stdout_path = outdir.join(&task.id.as_str()).with_extension("stdout");
     let task_stderr_path = outdir.join(&task.id.as_str()).with_extension("stderr");
     let task_stdout = std::fs::File::create(task_stdout_path).context(Temp)?;
     let task_stderr = std::fs::File::create(task_stderr_path).context(Temp)?;
 
-    let mut cmd = std::process::Command::new(&task.payload.program)
+    let token = acquire_job_token(jobserver, implicit_slot_free).context(Jobserver)?;
+
+    let mut command = std::process::Command::new(&task.payload.program);
+    command
         .args(&task.payload.arguments)
         .stdout(task_stdout)
-        .stderr(task_stderr)
-        .spawn()
-        .context(TaskStart {
+        .stderr(task_stderr);
+    // Let sub-makes/cargo invocations spawned by the task pull from the same
+    // job budget instead of oversubscribing the machine.
+    jobserver.configure(&mut command);
+
+    if let Some(task_sandbox) = &task.payload.sandbox {
+        sandbox::configure(&mut command, task_sandbox).context(Sandbox {
             id: task.id.clone(),
         })?;
+    }
 
-    let status = cmd.wait().context(TaskWait {
+    let mut child = command.spawn().context(TaskStart {
         id: task.id.clone(),
     })?;
 
-    Ok(status.success())
+    let status = child.wait().context(TaskWait {
+        id: task.id.clone(),
+    })?;
+
+    // Free the slot as soon as the task has actually finished running,
+    // rather than waiting for the (possibly slow) output hashing below to
+    // also complete, so other queued tasks can start sooner.
+    drop(token);
+
+    if status.success() {
+        let output_hashes = cache::hash_globs(&task.payload.outputs).context(Cache)?;
+        cache
+            .lock()
+            .unwrap()
+            .record(
+                &task.id,
+                cache::Fingerprint {
+                    hash: fingerprint,
+                    outputs: output_hashes,
+                },
+            )
+            .context(Cache)?;
+        Ok(RunResult::Success)
+    } else {
+        Ok(RunResult::Failed)
+    }
+}
+
+/// A job slot, either the one implicit slot every process is entitled to
+/// without talking to the jobserver, or a token read from the jobserver pipe.
+/// Releases itself on drop so the slot is always given back, no matter which
+/// way the holder's scope is exited (including an early `?` return).
+enum JobToken<'a> {
+    Implicit(&'a AtomicBool),
+    Acquired(jobserver::Acquired),
+}
+
+impl<'a> Drop for JobToken<'a> {
+    fn drop(&mut self) {
+        if let JobToken::Implicit(implicit_slot_free) = self {
+            implicit_slot_free.store(true, Ordering::Release);
+        }
+        // `Acquired`'s own `Drop` impl returns its byte to the jobserver pipe.
+    }
+}
+
+/// Claim a job slot, blocking until one becomes available.
+fn acquire_job_token<'a>(
+    jobserver: &JobserverClient,
+    implicit_slot_free: &'a AtomicBool,
+) -> std::io::Result<JobToken<'a>> {
+    if implicit_slot_free
+        .compare_exchange(true, false, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok()
+    {
+        Ok(JobToken::Implicit(implicit_slot_free))
+    } else {
+        jobserver.acquire().map(JobToken::Acquired)
+    }
 }
 
 struct TaskMessage {
@@ -186,6 +432,7 @@ struct TaskMessage {
 
 enum TaskOutcome {
     Success,
+    Cached,
     Failed,
     Error(Error),
 }
@@ -194,6 +441,10 @@ enum TaskOutcome {
 pub struct Cmd {
     pub program: OsString,
     pub arguments: Vec<OsString>,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    pub sandbox: Option<sandbox::Sandbox>,
+    pub fetch: Vec<fetch::Fetch>,
 }
 
 #[derive(Debug, Snafu)]
@@ -213,9 +464,30 @@ pub enum Error {
     #[snafu(display("Failed to compute execution plan: {}", source))]
     Planner { source: queue::Error },
 
+    #[snafu(display("Failed to render template for task {:?}: {}", id, source))]
+    Template {
+        id: queue::TaskId,
+        source: handlebars::RenderError,
+    },
+
     #[snafu(display("Failed to create temporary output: {}", source))]
     Temp { source: std::io::Error },
 
+    #[snafu(display("Failed to set up jobserver: {}", source))]
+    Jobserver { source: std::io::Error },
+
+    #[snafu(display("Failed to access task cache: {}", source))]
+    Cache { source: cache::Error },
+
+    #[snafu(display("Failed to set up sandbox for {:?}: {}", id, source))]
+    Sandbox {
+        id: queue::TaskId,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to fetch declared files: {}", source))]
+    Fetch { source: fetch::Error },
+
     #[snafu(display("Failed to spawn {:?}: {}", id, source))]
     TaskStart {
         id: queue::TaskId,
@@ -1,5 +1,6 @@
 use snafu::Snafu;
-use std::collections::{HashMap, HashSet};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::fmt::Display;
 
 /// Unique ID of tasks to be run.
@@ -29,6 +30,10 @@ impl From<&str> for TaskId {
 pub struct Task<P> {
     pub id: TaskId,
     pub needs: Vec<TaskId>,
+    /// Relative cost/duration hint of this task, used to schedule the
+    /// longest remaining dependency chain first once several tasks are
+    /// runnable at the same time.
+    pub cost: u64,
     pub payload: P,
 }
 
@@ -38,14 +43,49 @@ pub struct TaskQueue<P> {
     blocked: HashMap<TaskId, TaskState<P>>,
     /// Tasks indexed by reverse dependecy relationship
     needed_by: HashMap<TaskId, Vec<TaskId>>,
-    /// Set of tasks that can be run right now
-    available: Vec<Task<P>>,
+    /// Tasks that can be run right now, ordered by descending bottom level
+    /// (the HLFET heuristic), so the task on the longest remaining chain is
+    /// always handed out first.
+    available: BinaryHeap<HeapEntry<P>>,
 }
 
 #[derive(Debug)]
 struct TaskState<P> {
     task: Task<P>,
     remaining_needs: HashSet<TaskId>,
+    bottom_level: u64,
+}
+
+/// Wraps a runnable task with its precomputed bottom level so `available`
+/// can be a max-heap over it.
+#[derive(Debug)]
+struct HeapEntry<P> {
+    bottom_level: u64,
+    task: Task<P>,
+}
+
+impl<P> PartialEq for HeapEntry<P> {
+    fn eq(&self, other: &Self) -> bool {
+        self.bottom_level == other.bottom_level && self.task.id == other.task.id
+    }
+}
+
+impl<P> Eq for HeapEntry<P> {}
+
+impl<P> PartialOrd for HeapEntry<P> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<P> Ord for HeapEntry<P> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Ties are broken by task id, smallest first, so scheduling is
+        // deterministic regardless of insertion order.
+        self.bottom_level
+            .cmp(&other.bottom_level)
+            .then_with(|| other.task.id.cmp(&self.task.id))
+    }
 }
 
 impl<P> Default for TaskQueue<P> {
@@ -53,7 +93,7 @@ impl<P> Default for TaskQueue<P> {
         Self {
             blocked: HashMap::new(),
             needed_by: HashMap::new(),
-            available: Vec::new(),
+            available: BinaryHeap::new(),
         }
     }
 }
@@ -63,9 +103,10 @@ impl<P> TaskQueue<P> {
         QueuePlanner::new(tasks).plan()
     }
 
-    /// Remove a task from the available set.
+    /// Remove the task on the longest remaining dependency chain from the
+    /// available set.
     pub fn pop_available(&mut self) -> Option<Task<P>> {
-        self.available.pop()
+        self.available.pop().map(|entry| entry.task)
     }
 
     /// Unblocks tasks that depended on the task that was done.
@@ -79,10 +120,47 @@ impl<P> TaskQueue<P> {
                     .remaining_needs;
                 if needs.remove(task) && needs.is_empty() {
                     let state = self.blocked.remove(&dependent).expect("Known to be there");
-                    self.available.push(state.task);
+                    self.available.push(HeapEntry {
+                        bottom_level: state.bottom_level,
+                        task: state.task,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Report a task as failed, transitively removing every task (directly
+    /// or indirectly) depending on it from `blocked` and returning them, so
+    /// the caller can report them as skipped rather than leaving them to
+    /// linger until `give_up`.
+    pub fn mark_failed(&mut self, task: &TaskId) -> Vec<Task<P>> {
+        let mut skipped = Vec::new();
+        let mut stack = vec![task.clone()];
+
+        while let Some(current) = stack.pop() {
+            if let Some(dependents) = self.needed_by.remove(&current) {
+                for dependent in dependents {
+                    if let Some(state) = self.blocked.remove(&dependent) {
+                        // `dependent` is gone from `blocked` now, so scrub it
+                        // from the `needed_by` list of every other need it
+                        // was waiting on too, otherwise a sibling need
+                        // finishing later would try to unblock a task that
+                        // no longer exists.
+                        for need in &state.task.needs {
+                            if need != &current {
+                                if let Some(waiters) = self.needed_by.get_mut(need) {
+                                    waiters.retain(|id| id != &dependent);
+                                }
+                            }
+                        }
+                        stack.push(dependent);
+                        skipped.push(state.task);
+                    }
                 }
             }
         }
+
+        skipped
     }
 
     /// Stop processing and return the remaining tasks.
@@ -93,6 +171,9 @@ impl<P> TaskQueue<P> {
             .collect()
     }
 
+    /// Record `task`, deferring the available/blocked decision until
+    /// `finish`, since bottom levels can only be computed once every task's
+    /// dependents are known.
     fn insert(&mut self, task: Task<P>) {
         for need in &task.needs {
             self.needed_by
@@ -101,17 +182,52 @@ impl<P> TaskQueue<P> {
                 .push(task.id.clone());
         }
 
-        if task.needs.is_empty() {
-            self.available.push(task);
-        } else {
-            self.blocked.insert(
-                task.id.clone(),
-                TaskState {
-                    remaining_needs: task.needs.iter().cloned().collect(),
-                    task,
-                },
-            );
+        self.blocked.insert(
+            task.id.clone(),
+            TaskState {
+                remaining_needs: task.needs.iter().cloned().collect(),
+                bottom_level: 0,
+                task,
+            },
+        );
+    }
+
+    /// Compute the HLFET bottom level of every task and move the ones
+    /// without any needs into `available`. `order` must be a topological
+    /// order of all inserted tasks (dependencies before dependents), which
+    /// the DFS planner produces as a side effect.
+    fn finish(mut self, order: &[TaskId]) -> Self {
+        // Processing in reverse-topological order means a task's dependents
+        // (its successors in `needed_by`) always have their bottom level
+        // computed already.
+        for id in order.iter().rev() {
+            let cost = self.blocked[id].task.cost;
+            let successors_bottom_level = self
+                .needed_by
+                .get(id)
+                .and_then(|successors| {
+                    successors
+                        .iter()
+                        .map(|successor| self.blocked[successor].bottom_level)
+                        .max()
+                })
+                .unwrap_or(0);
+
+            self.blocked.get_mut(id).expect("inserted above").bottom_level =
+                cost + successors_bottom_level;
         }
+
+        for id in order {
+            if self.blocked[id].remaining_needs.is_empty() {
+                let state = self.blocked.remove(id).expect("inserted above");
+                self.available.push(HeapEntry {
+                    bottom_level: state.bottom_level,
+                    task: state.task,
+                });
+            }
+        }
+
+        self
     }
 }
 
@@ -122,6 +238,9 @@ struct QueuePlanner<P> {
     visiting: HashSet<TaskId>,
     plan: TaskQueue<P>,
     stack: Vec<TaskId>,
+    /// Topological order in which tasks were inserted into `plan`
+    /// (dependencies before dependents).
+    order: Vec<TaskId>,
 }
 
 impl<P> QueuePlanner<P> {
@@ -133,6 +252,7 @@ impl<P> QueuePlanner<P> {
             visiting: HashSet::new(),
             plan: TaskQueue::default(),
             stack: Vec::new(),
+            order: Vec::new(),
         }
     }
 
@@ -140,7 +260,7 @@ impl<P> QueuePlanner<P> {
         while let Some(key) = self.taskmap.keys().next().cloned() {
             self.topo(&key)?;
         }
-        Ok(self.plan)
+        Ok(self.plan.finish(&self.order))
     }
 
     fn topo(&mut self, current: &TaskId) -> Result<()> {
@@ -179,6 +299,7 @@ impl<P> QueuePlanner<P> {
             self.topo(needed)?;
         }
         // Then insert current
+        self.order.push(current.clone());
         self.plan.insert(task);
 
         self.stack.pop();
@@ -219,3 +340,36 @@ impl<'a> Display for DisplayChain<'a> {
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(id: &str, needs: &[&str]) -> Task<()> {
+        Task {
+            id: id.into(),
+            needs: needs.iter().map(|&n| n.into()).collect(),
+            cost: 1,
+            payload: (),
+        }
+    }
+
+    /// `c` needs both `a` and `b`. If `a` fails, `c` is skipped and dropped
+    /// from `blocked`; `b` finishing afterwards must not try to unblock the
+    /// already-skipped `c` again.
+    #[test]
+    fn mark_failed_then_mark_done_sibling_does_not_panic() {
+        let mut queue: TaskQueue<()> =
+            TaskQueue::new(vec![task("a", &[]), task("b", &[]), task("c", &["a", "b"])])
+                .unwrap();
+
+        let skipped = queue.mark_failed(&"a".into());
+        assert_eq!(
+            skipped.into_iter().map(|t| t.id).collect::<Vec<_>>(),
+            vec![TaskId::from("c")]
+        );
+
+        // Must not panic looking up "c" in `blocked`.
+        queue.mark_done(&"b".into());
+    }
+}
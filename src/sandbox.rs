@@ -0,0 +1,209 @@
+//! Optional per-task sandboxing using Linux namespaces.
+//!
+//! When a task declares a `[sandbox]` section it is executed in a fresh
+//! user, mount, pid and (unless `network` is enabled) net namespace, with
+//! its root filesystem replaced by a private, otherwise-empty root built
+//! from only the declared `mounts` (read-only) and `scratch` (writable
+//! tmpfs): the task can't see or touch the rest of the host filesystem at
+//! all, only what it explicitly declared. Network access is dropped by
+//! default. Unsupported on non-Linux hosts.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sandbox {
+    /// Working directory the task runs in, inside the sandbox
+    #[serde(default)]
+    pub workdir: Option<PathBuf>,
+
+    /// Host paths to bind-mount read-only, keyed by the path they should
+    /// appear at inside the sandbox
+    #[serde(default)]
+    pub mounts: HashMap<String, String>,
+
+    /// Directory backed by a private, writable tmpfs, for task outputs
+    #[serde(default)]
+    pub scratch: Option<PathBuf>,
+
+    /// Whether the task may access the network. Isolated by default.
+    #[serde(default)]
+    pub network: bool,
+}
+
+/// Arrange for `command` to run inside `sandbox` once spawned. `workdir` is
+/// resolved inside the sandbox's private root (built in `enter`, long after
+/// `command` would otherwise have `chdir`'d on the host root), so it's
+/// threaded through to `enter` rather than set on `command` directly.
+pub fn configure(command: &mut Command, sandbox: &Sandbox) -> io::Result<()> {
+    configure_impl(command, sandbox)
+}
+
+#[cfg(unix)]
+fn configure_impl(command: &mut Command, sandbox: &Sandbox) -> io::Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let sandbox = sandbox.clone();
+    let program = to_cstring(command.get_program().to_owned());
+    let args: Vec<CString> = std::iter::once(command.get_program().to_owned())
+        .chain(command.get_args().map(|arg| arg.to_owned()))
+        .map(to_cstring)
+        .collect();
+    unsafe {
+        command.pre_exec(move || enter(&sandbox, &program, &args));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn configure_impl(_command: &mut Command, _sandbox: &Sandbox) -> io::Result<()> {
+    Err(unsupported())
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "task sandboxing requires Linux namespaces",
+    )
+}
+
+#[cfg(unix)]
+fn to_cstring(s: std::ffi::OsString) -> CString {
+    use std::os::unix::ffi::OsStringExt;
+    CString::new(s.into_vec()).expect("argv strings must not contain NUL bytes")
+}
+
+/// Join an absolute sandbox-side path onto `root`, e.g. `/usr/bin` under
+/// `root` becomes `root/usr/bin`.
+#[cfg(target_os = "linux")]
+fn under_root(root: &Path, sandbox_path: &str) -> PathBuf {
+    root.join(sandbox_path.trim_start_matches('/'))
+}
+
+/// Unshares fresh namespaces for the task, replaces the root filesystem with
+/// a private root containing only the declared mounts, and forks (since
+/// `unshare(2)` never moves the calling process itself into a new pid
+/// namespace, only children forked after the call), then execs
+/// `program`/`args` in that child. The parent (which is the process std's
+/// `Command` forked and is about to exec in, absent this detour) instead
+/// waits for the child and exits with its status, so the task really runs
+/// as pid 1 of its own namespace rather than with its real host pid.
+#[cfg(target_os = "linux")]
+fn enter(sandbox: &Sandbox, program: &CString, args: &[CString]) -> io::Result<()> {
+    use nix::mount::{mount, umount2, MntFlags, MsFlags};
+    use nix::sched::{unshare, CloneFlags};
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{chdir, execvp, fork, getgid, getuid, pivot_root, ForkResult};
+
+    fn to_io(err: nix::Error) -> io::Error {
+        io::Error::from_raw_os_error(err as i32)
+    }
+
+    let uid = getuid();
+    let gid = getgid();
+
+    let mut flags =
+        CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWPID;
+    if !sandbox.network {
+        flags |= CloneFlags::CLONE_NEWNET;
+    }
+    unshare(flags).map_err(to_io)?;
+
+    // Map our real uid/gid to root inside the fresh user namespace. This is
+    // what lets an unprivileged user unshare mount/pid/net namespaces and do
+    // the mounts and pivot_root below, which otherwise require CAP_SYS_ADMIN
+    // on the host.
+    std::fs::write("/proc/self/setgroups", "deny")?;
+    std::fs::write("/proc/self/uid_map", format!("0 {} 1", uid))?;
+    std::fs::write("/proc/self/gid_map", format!("0 {} 1", gid))?;
+
+    // Make sure none of the mounts we're about to do leak back out to the host.
+    mount::<str, str, str, str>(None, "/", None, MsFlags::MS_REC | MsFlags::MS_PRIVATE, None)
+        .map_err(to_io)?;
+
+    // Build a private root as a throwaway tmpfs, populated with only the
+    // declared mounts, and `pivot_root` into it below. Without this, the
+    // declared mounts/scratch are just extra overlays on top of the host's
+    // existing filesystem tree, which the task could still read and write
+    // through its normal paths.
+    let new_root = std::env::temp_dir().join(format!("auto-sandbox-{}", std::process::id()));
+    std::fs::create_dir_all(&new_root)?;
+    mount::<_, _, str, str>(None::<&str>, &new_root, Some("tmpfs"), MsFlags::empty(), None)
+        .map_err(to_io)?;
+
+    for (sandbox_path, host_path) in &sandbox.mounts {
+        let target = under_root(&new_root, sandbox_path);
+        if Path::new(host_path).is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else {
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::File::create(&target)?;
+        }
+
+        mount::<_, _, str, str>(
+            Some(Path::new(host_path)),
+            &target,
+            None,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            None,
+        )
+        .map_err(to_io)?;
+        // A plain MS_BIND ignores MS_RDONLY, so the remount pass is what
+        // actually makes the mount read-only.
+        mount::<str, _, str, str>(
+            None,
+            &target,
+            None,
+            MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+            None,
+        )
+        .map_err(to_io)?;
+    }
+
+    if let Some(scratch) = &sandbox.scratch {
+        let target = under_root(&new_root, &scratch.to_string_lossy());
+        std::fs::create_dir_all(&target)?;
+        mount::<_, _, str, str>(None::<&str>, &target, Some("tmpfs"), MsFlags::empty(), None)
+            .map_err(to_io)?;
+    }
+
+    // `pivot_root` needs somewhere inside the new root to stash the old one.
+    let old_root_name = ".auto-sandbox-old-root";
+    std::fs::create_dir_all(new_root.join(old_root_name))?;
+
+    chdir(&new_root).map_err(to_io)?;
+    pivot_root(".", old_root_name).map_err(to_io)?;
+    chdir("/").map_err(to_io)?;
+    // Detach the old root (the entire pre-sandbox filesystem) rather than
+    // leaving it reachable under `/old_root_name`.
+    umount2(format!("/{}", old_root_name).as_str(), MntFlags::MNT_DETACH).map_err(to_io)?;
+    std::fs::remove_dir(format!("/{}", old_root_name)).ok();
+
+    if let Some(workdir) = &sandbox.workdir {
+        chdir(workdir.as_path()).map_err(to_io)?;
+    }
+
+    // `CLONE_NEWPID` only takes effect on processes forked from here on, so
+    // the task has to be that forked child for the new namespace to apply.
+    match unsafe { fork() }.map_err(to_io)? {
+        ForkResult::Parent { child } => loop {
+            match waitpid(child, None).map_err(to_io)? {
+                WaitStatus::Exited(_, code) => std::process::exit(code),
+                WaitStatus::Signaled(_, signal, _) => std::process::exit(128 + signal as i32),
+                _ => continue,
+            }
+        },
+        ForkResult::Child => execvp(program, args).map(|_| ()).map_err(to_io),
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn enter(_sandbox: &Sandbox, _program: &CString, _args: &[CString]) -> io::Result<()> {
+    Err(unsupported())
+}